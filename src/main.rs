@@ -1,12 +1,20 @@
-use std::net::{Ipv4Addr, IpAddr, SocketAddr, TcpListener, TcpStream};
-use std::thread;
-use std::time::Duration;
-use std::sync::{Arc, Mutex};
-use std::io::{Read, Write};
-use std::collections::HashMap;
-use std::io;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token};
+use serde::{Deserialize, Serialize};
 
 #[cfg(debug_assertions)]
 macro_rules! debug {
@@ -18,147 +26,791 @@ macro_rules! debug {
   ($( $args:expr ),*) => {}
 }
 
-#[derive(Clone)]
+/* Fixed token identifying readiness events for the listening socket, every
+ * accepted stream is tracked with a token built from its connection id */
+const LISTENER: Token = Token(0);
+
+/* Width of the rolling window the per-connection rate limit is enforced over */
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/* How often the background reporter prints throughput, and how often the
+ * event loop wakes up on its own to check rate-limit windows and the report */
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/* Default number of recent messages kept for replay and the default file
+ * history is persisted to, both overridable from the command line */
+const DEFAULT_LOG_CAPACITY: usize = 100;
+const DEFAULT_LOG_PATH: &str = "history.jsonl";
+
+/* Largest payload a single length-prefixed frame may declare; a connection
+ * that declares more is closed rather than left to buffer an
+ * attacker-controlled amount of data while the frame trickles in */
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
 struct Conn {
-  stream: Arc<Mutex<TcpStream>>,
-  connections: Connections,
+  stream: TcpStream,
+  id: u32,
+  /* Display name announced over the "NAME <nick>" handshake, unset until then */
+  name: Option<String>,
+  /* Bytes read off the socket that haven't formed a complete frame yet */
+  read_buffer: Vec<u8>,
+  /* Bytes that still need to be written once the socket becomes writable again */
+  write_queue: Vec<u8>,
+  /* The interest we are currently registered with, or None if deregistered
+   * entirely (happens while rate-limited with nothing queued to write) */
+  registered: Option<Interest>,
+  /* Whether read interest has been dropped because this connection is over budget */
+  limited: bool,
+  /* Lifetime throughput counters */
+  bytes_read: u64,
+  bytes_written: u64,
+  /* Counters as of the last throughput report, used to compute a rate */
+  last_report_read: u64,
+  last_report_written: u64,
+  /* Bytes read so far in the current rate-limit window */
+  window_read: u64,
+  window_start: Instant,
 }
 
 impl Conn {
-  fn read(&self, mut buf: &mut [u8]) -> std::io::Result<usize> {
-    self.stream.lock().unwrap().read(&mut buf)
+  fn new(stream: TcpStream, id: u32, now: Instant) -> Conn {
+    Conn {
+      stream,
+      id,
+      name: None,
+      read_buffer: Vec::new(),
+      write_queue: Vec::new(),
+      registered: Some(Interest::READABLE),
+      limited: false,
+      bytes_read: 0,
+      bytes_written: 0,
+      last_report_read: 0,
+      last_report_written: 0,
+      window_read: 0,
+      window_start: now,
+    }
+  }
+
+  fn token(&self) -> Token {
+    Token(self.id as usize)
+  }
+
+  /* Read everything currently available off the socket (up to the
+   * per-window rate limit, if any) and pop any complete length-prefixed
+   * frames out of the buffer, leaving partial frames for the next call.
+   * Returns (frames, keep_alive, over_budget) - over_budget means the
+   * window was exhausted and read interest should be dropped for now;
+   * keep_alive also goes false if a frame declared a length over
+   * MAX_FRAME_LEN, closing the connection rather than buffering it */
+  fn read_frames(&mut self, limit: Option<u64>) -> io::Result<(Vec<Vec<u8>>, bool, bool)> {
+    let mut buf = [0u8; 1024];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => return Ok(self.collect_frames(false, false)),
+        Ok(read) => {
+          self.read_buffer.extend_from_slice(&buf[..read]);
+          self.bytes_read += read as u64;
+          self.window_read += read as u64;
+          if let Some(limit) = limit {
+            if self.window_read >= limit {
+              return Ok(self.collect_frames(true, true));
+            }
+          }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(_e) => return Ok(self.collect_frames(false, false)),
+      }
+    }
+    Ok(self.collect_frames(true, false))
+  }
+
+  /* Pop complete frames out of the read buffer and fold in whether an
+   * oversized declared length was seen, forcing keep_alive false so the
+   * caller tears the connection down like it would for a read error */
+  fn collect_frames(&mut self, keep_alive: bool, over_budget: bool) -> (Vec<Vec<u8>>, bool, bool) {
+    let (frames, oversized) = self.pop_frames();
+    if oversized {
+      debug!("[{}] Declared frame length exceeds {} bytes, closing connection", self.id, MAX_FRAME_LEN);
+    }
+    (frames, keep_alive && !oversized, over_budget)
+  }
+
+  /* Pop every complete frame (4-byte big-endian length + payload) currently
+   * sitting in the read buffer. Returns frames alongside whether a frame
+   * declared a length over MAX_FRAME_LEN, at which point popping stops
+   * since the connection is about to be closed. */
+  fn pop_frames(&mut self) -> (Vec<Vec<u8>>, bool) {
+    let mut frames = Vec::new();
+    while self.read_buffer.len() >= 4 {
+      let len = u32::from_be_bytes(self.read_buffer[0..4].try_into().unwrap()) as usize;
+      if len > MAX_FRAME_LEN {
+        return (frames, true);
+      }
+      if self.read_buffer.len() < 4 + len {
+        break;
+      }
+      frames.push(self.read_buffer[4..4 + len].to_vec());
+      self.read_buffer.drain(0..4 + len);
+    }
+    (frames, false)
+  }
+
+  /* Queue a buffer for writing, attempting to flush immediately so the
+   * common case (socket not backed up) never touches the queue at all */
+  fn queue_write(&mut self, buf: &[u8]) {
+    if !self.write_queue.is_empty() {
+      self.write_queue.extend_from_slice(buf);
+      return;
+    }
+    match self.stream.write(buf) {
+      Ok(written) if written == buf.len() => { self.bytes_written += written as u64; },
+      Ok(written) => {
+        self.bytes_written += written as u64;
+        self.write_queue.extend_from_slice(&buf[written..]);
+      },
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+        self.write_queue.extend_from_slice(buf);
+      },
+      Err(_e) => { debug!("[{}] Error writing to connection {}", self.id, _e); },
+    }
+  }
+
+  /* Drain as much of the write queue as the socket will currently accept,
+   * leaving whatever is left for the next writable readiness event */
+  fn flush(&mut self) -> io::Result<()> {
+    while !self.write_queue.is_empty() {
+      match self.stream.write(&self.write_queue) {
+        Ok(written) => {
+          self.bytes_written += written as u64;
+          self.write_queue.drain(0..written);
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
   }
-  fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
-    match self.stream.try_lock() {
-      Ok(mut lock) => {lock.write(buf)},
-      Err(_e) => {Ok(0)},
+
+  /* Reconcile registered interest with what this connection currently
+   * wants: readable unless it is rate-limited, writable only while there is
+   * something queued to send. Drops the registration entirely rather than
+   * busy-polling a connection that wants neither. */
+  fn sync_interest(&mut self, registry: &Registry) -> io::Result<()> {
+    let desired = match (!self.limited, !self.write_queue.is_empty()) {
+      (false, false) => None,
+      (true, false) => Some(Interest::READABLE),
+      (false, true) => Some(Interest::WRITABLE),
+      (true, true) => Some(Interest::READABLE | Interest::WRITABLE),
+    };
+    if desired == self.registered {
+      return Ok(());
+    }
+    let token = self.token();
+    match (self.registered, desired) {
+      (Some(_), Some(want)) => registry.reregister(&mut self.stream, token, want)?,
+      (Some(_), None) => registry.deregister(&mut self.stream)?,
+      (None, Some(want)) => registry.register(&mut self.stream, token, want)?,
+      (None, None) => {},
     }
+    self.registered = desired;
+    Ok(())
   }
-  fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
-    self.stream.lock().unwrap().take_error()
+
+  /* Close both halves of the socket immediately rather than leaving it for
+   * the kernel to notice the peer dropped, used on explicit removal and
+   * server shutdown so half-open sockets aren't left lingering */
+  fn shutdown(&mut self) {
+    if let Err(_e) = self.stream.shutdown(Shutdown::Both) {
+      debug!("[{}] Error shutting down connection {}", self.id, _e);
+    }
   }
 }
 
-#[derive(Clone)]
 struct Connections {
-  counter: Arc<Mutex<u32>>,
-  connections: Arc<Mutex<HashMap<u32, Conn>>>,
+  counter: u32,
+  connections: HashMap<u32, Conn>,
+  registry: Registry,
+  /* Per-connection bytes-per-second ceiling, unlimited if None */
+  rate_limit: Option<u64>,
+  last_report: Instant,
+  /* When set, each length-prefixed frame carries a JSON command/event
+   * instead of opaque plaintext broadcast */
+  json_mode: bool,
+  /* Ring buffer of recently broadcast messages, replayed to new connections
+   * and persisted to `log_path` as it grows */
+  log: VecDeque<LogEntry>,
+  log_capacity: usize,
+  log_path: Option<PathBuf>,
 }
 
 impl Connections {
-  fn store(&self, conn: Conn) -> u32 {
-    let mut counter = self.counter.lock().unwrap();
-    *counter += 1;
-    let id = *counter;
-    self.connections.lock().unwrap().insert(id, conn);
-    return id;
-  }
-  fn remove(&self, id: u32) {
-    self.connections.lock().unwrap().remove(&id);
-  }
-  fn broadcast(&self, buf: &[u8]) {
-    /* Loop over all connections in map and write the given buffer */
-    for (id, conn) in self.connections.lock().unwrap().iter() {
-      match conn.write(&buf) {
-        Ok(size) => { debug!("[{}] Wrote {} to connection...", id, size); },
-        Err(e) => { debug!("[{}] Error writing to connection {}", id, e); },
+  fn new(
+    registry: Registry,
+    rate_limit: Option<u64>,
+    json_mode: bool,
+    log: VecDeque<LogEntry>,
+    log_capacity: usize,
+    log_path: Option<PathBuf>,
+    now: Instant,
+  ) -> Connections {
+    Connections {
+      counter: 0,
+      connections: HashMap::new(),
+      registry,
+      rate_limit,
+      last_report: now,
+      json_mode,
+      log,
+      log_capacity,
+      log_path,
+    }
+  }
+
+  /* Register a freshly accepted stream, store it in the map and replay the
+   * persisted message log to it before any live traffic arrives, returns
+   * the id it was assigned */
+  fn store(&mut self, stream: TcpStream, now: Instant) -> io::Result<u32> {
+    self.counter += 1;
+    let id = self.counter;
+    let mut conn = Conn::new(stream, id, now);
+    let token = conn.token();
+    self.registry.register(&mut conn.stream, token, Interest::READABLE)?;
+    self.connections.insert(id, conn);
+    let replay: Vec<Vec<u8>> = self.log.iter().map(|entry| entry.payload.clone().into_bytes()).collect();
+    for payload in replay {
+      self.unicast(id, &payload);
+    }
+    Ok(id)
+  }
+
+  /* Append a broadcast message to the log, persisting it to disk and
+   * trimming the oldest entry once the buffer is over capacity */
+  fn record(&mut self, sender_id: Option<u32>, payload: &[u8]) {
+    let entry = LogEntry {
+      timestamp: Utc::now().to_rfc3339(),
+      sender_id,
+      json_mode: self.json_mode,
+      payload: String::from_utf8_lossy(payload).into_owned(),
+    };
+    if let Some(path) = &self.log_path {
+      if let Err(_e) = append_log_entry(path, &entry) {
+        debug!("Error persisting log entry {}", _e);
       }
     }
+    self.log.push_back(entry);
+    while self.log.len() > self.log_capacity {
+      self.log.pop_front();
+    }
   }
-  pub fn new() -> Connections {
-    Connections { 
-      counter: Arc::new(Mutex::new(0)),
-      connections: Arc::new(Mutex::new(HashMap::new())),
+
+  /* Shut down and drop a connection, this is a no-op if it is already gone */
+  fn remove(&mut self, id: u32) {
+    if let Some(mut conn) = self.connections.remove(&id) {
+      conn.shutdown();
+      if conn.registered.is_some() {
+        let _ = self.registry.deregister(&mut conn.stream);
+      }
     }
   }
-}
 
-fn handle_stream(conn: Conn) -> std::io::Result<()> {
-  /* Store the connection in the shared map */
-  let id = conn.connections.store(conn.clone());
-  println!("[{}] Connected...", id);
-  /* Start loop to read from socket */
-  loop {
-    /* Close if there was an error */
-    match conn.take_error() {
-      Ok(_) => {},
-      Err(_e) => {
-        break;
-      },
+  /* Shut down every connection, used when the server itself is exiting */
+  fn shutdown_all(&mut self) {
+    for conn in self.connections.values_mut() {
+      conn.shutdown();
     }
+  }
 
-    /* Read from socket (buf size: 1024) */
-    let mut buf = vec![0; 1024];
-    match conn.read(&mut buf) {
-      /* If 0 bytes were read the socket has been closed */
-      Ok(read) if read == 0 => {break;},
-      Ok(_read) => {
-        /* Convert raw bytes to string */
-        let string = String::from_utf8_lossy(&buf);
-        /* Prefix connection id to the message */
-        let mut message = format!("[{}] ", id);
-        message.push_str(&string);
-        /* Broadcast message to other sockets */
-        conn.connections.broadcast(message.as_bytes());
-      },
-      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-        /* Sleep for blocking error, an implementation of wait_for_fd would be better */
-        thread::sleep(Duration::from_millis(10));
+  /* Loop over all connections in map and write the given buffer, length
+   * prefixed so the other end can pull whole frames back out. When `persist`
+   * is set the broadcast is also appended to the message log under
+   * `sender_id`; ephemeral server notices (e.g. a shutdown warning) should
+   * pass `persist: false` so they are never replayed to a future connection
+   * or evict real chat history out of the capacity-bounded log. */
+  fn broadcast(&mut self, sender_id: Option<u32>, buf: &[u8], persist: bool) {
+    let framed = frame(buf);
+    for (_id, conn) in self.connections.iter_mut() {
+      conn.queue_write(&framed);
+      if let Err(_e) = conn.sync_interest(&self.registry) {
+        debug!("[{}] Error updating interest {}", _id, _e);
+      }
+    }
+    if persist {
+      self.record(sender_id, buf);
+    }
+  }
+
+  /* Write a framed buffer to a single connection, used for handshake
+   * replies that should not go out to everybody */
+  fn unicast(&mut self, id: u32, buf: &[u8]) {
+    let framed = frame(buf);
+    if let Some(conn) = self.connections.get_mut(&id) {
+      conn.queue_write(&framed);
+      if let Err(_e) = conn.sync_interest(&self.registry) {
+        debug!("[{}] Error updating interest {}", id, _e);
+      }
+    }
+  }
+
+  /* Serialize and broadcast a typed event, used in JSON protocol mode in
+   * place of the plaintext `broadcast`; see `broadcast` for `persist` */
+  fn broadcast_event(&mut self, sender_id: Option<u32>, event: &ServerEvent, persist: bool) {
+    match serde_json::to_vec(event) {
+      Ok(buf) => self.broadcast(sender_id, &buf, persist),
+      Err(_e) => { debug!("Error serializing event {}", _e); },
+    }
+  }
+
+  /* Serialize and send a typed event to a single connection, used for
+   * directed replies such as `list` in JSON protocol mode */
+  fn send_to(&mut self, id: u32, event: &ServerEvent) {
+    match serde_json::to_vec(event) {
+      Ok(buf) => self.unicast(id, &buf),
+      Err(_e) => { debug!("[{}] Error serializing event {}", id, _e); },
+    }
+  }
+
+  /* Tell everyone a connection left, in whichever wire format is active */
+  fn announce_leave(&mut self, id: u32, name: String) {
+    if self.json_mode {
+      self.broadcast_event(Some(id), &ServerEvent::Left { name }, true);
+    } else {
+      self.broadcast(Some(id), format!("{} left", name).as_bytes(), true);
+    }
+  }
+
+  /* Register a display name for a connection, rejecting names already in use */
+  fn register_name(&mut self, id: u32, name: String) -> Result<(), String> {
+    if name.is_empty() {
+      return Err("name must not be empty".to_string());
+    }
+    let taken = self.connections.values().any(|conn| conn.name.as_deref() == Some(name.as_str()));
+    if taken {
+      return Err(format!("name '{}' is already taken", name));
+    }
+    if let Some(conn) = self.connections.get_mut(&id) {
+      conn.name = Some(name);
+    }
+    Ok(())
+  }
+
+  /* The name a connection is currently known by, if it has registered one */
+  fn name_of(&self, id: u32) -> Option<String> {
+    self.connections.get(&id).and_then(|conn| conn.name.clone())
+  }
+
+  /* Flush whatever is queued for a connection that just became writable */
+  fn handle_writable(&mut self, id: u32) -> bool {
+    let conn = match self.connections.get_mut(&id) {
+      Some(conn) => conn,
+      None => return false,
+    };
+    if conn.flush().is_err() {
+      return false;
+    }
+    conn.sync_interest(&self.registry).is_ok()
+  }
+
+  /* Drain a readable connection (subject to its rate-limit window),
+   * handling the nickname handshake, broadcasting any chat frames and
+   * honouring an explicit QUIT, returns false once the connection should
+   * be torn down */
+  fn handle_readable(&mut self, id: u32) -> bool {
+    let (frames, keep) = {
+      let conn = match self.connections.get_mut(&id) {
+        Some(conn) => conn,
+        None => return false,
+      };
+      let (frames, keep, over_budget) = match conn.read_frames(self.rate_limit) {
+        Ok(result) => result,
+        Err(_e) => return false,
+      };
+      if over_budget {
+        conn.limited = true;
+        debug!("[{}] Rate limit exceeded, pausing reads", id);
+      }
+      if let Err(_e) = conn.sync_interest(&self.registry) {
+        debug!("[{}] Error updating interest {}", id, _e);
+      }
+      (frames, keep)
+    };
+
+    let mut quit = false;
+    for payload in frames {
+      if self.json_mode {
+        if !self.handle_json_command(id, &payload) {
+          quit = true;
+        }
+        continue;
+      }
+
+      let text = String::from_utf8_lossy(&payload).into_owned();
+
+      /* Until a display name is registered, a "NAME <nick>" frame registers
+       * it; other frames sent before then are broadcast under the numeric id */
+      if self.name_of(id).is_none() {
+        if let Some(nick) = text.strip_prefix("NAME ") {
+          let nick = nick.trim().to_string();
+          match self.register_name(id, nick.clone()) {
+            Ok(()) => {
+              println!("[{}] {} joined", id, nick);
+              self.broadcast(Some(id), format!("{} joined", nick).as_bytes(), true);
+            },
+            Err(e) => self.unicast(id, e.as_bytes()),
+          }
+          continue;
+        }
+      }
+
+      /* A bare QUIT line asks the server to close this connection cleanly
+       * rather than waiting for the socket to drop */
+      if text.trim() == "QUIT" {
+        quit = true;
+        continue;
+      }
+
+      /* Prefix the sender's display name to the message */
+      let mut message = format!("[{}] ", self.name_of(id).unwrap_or_else(|| id.to_string()));
+      message.push_str(&text);
+      self.broadcast(Some(id), message.as_bytes(), true);
+    }
+    keep && !quit
+  }
+
+  /* Parse one framed payload as a `ClientCommand` and react to it: `nick`
+   * and `msg` broadcast a typed event, `list` replies only to the sender,
+   * `quit` returns false to ask the caller to tear down the connection */
+  fn handle_json_command(&mut self, id: u32, payload: &[u8]) -> bool {
+    let command: ClientCommand = match serde_json::from_slice(payload) {
+      Ok(command) => command,
+      Err(e) => {
+        self.send_to(id, &ServerEvent::Error { message: format!("invalid command: {}", e) });
+        return true;
       },
-      Err(_e) => {break},
     };
+
+    match command {
+      ClientCommand::Nick { name } => {
+        /* As with the plaintext "NAME" handshake, a nick only joins once per
+         * connection so `Joined` is not re-broadcast (and re-logged) on
+         * every repeat `nick` command */
+        if self.name_of(id).is_some() {
+          self.send_to(id, &ServerEvent::Error { message: "already joined".to_string() });
+          return true;
+        }
+        match self.register_name(id, name.clone()) {
+          Ok(()) => {
+            println!("[{}] {} joined", id, name);
+            self.broadcast_event(Some(id), &ServerEvent::Joined { name }, true);
+          },
+          Err(message) => self.send_to(id, &ServerEvent::Error { message }),
+        }
+        true
+      },
+      ClientCommand::Msg { body } => {
+        let from = self.name_of(id).unwrap_or_else(|| id.to_string());
+        self.broadcast_event(Some(id), &ServerEvent::Msg { from, body }, true);
+        true
+      },
+      ClientCommand::List => {
+        let connections = self
+          .connections
+          .iter()
+          .map(|(&id, conn)| ListEntry { id, name: conn.name.clone() })
+          .collect();
+        self.send_to(id, &ServerEvent::List { connections });
+        true
+      },
+      ClientCommand::Quit => false,
+    }
   }
-  /* After loop finishes remove from shared map */
-  conn.connections.remove(id);
-  println!("[{}] Disconnected...", id);
 
-  Ok(())
+  /* Roll over any rate-limit windows that have elapsed, re-enabling reads
+   * for connections that were paused, and print the periodic throughput
+   * report once REPORT_INTERVAL has elapsed */
+  fn tick(&mut self) {
+    let now = Instant::now();
+    for conn in self.connections.values_mut() {
+      if now.duration_since(conn.window_start) >= RATE_WINDOW {
+        conn.window_start = now;
+        conn.window_read = 0;
+        if conn.limited {
+          conn.limited = false;
+          if let Err(_e) = conn.sync_interest(&self.registry) {
+            debug!("[{}] Error updating interest {}", conn.id, _e);
+          }
+        }
+      }
+    }
+    if now.duration_since(self.last_report) >= REPORT_INTERVAL {
+      self.report(now);
+    }
+  }
+
+  /* Print aggregate and per-connection throughput since the last report */
+  fn report(&mut self, now: Instant) {
+    let elapsed = now.duration_since(self.last_report).as_secs_f64();
+    let mut total_read = 0u64;
+    let mut total_written = 0u64;
+    for conn in self.connections.values_mut() {
+      let read_delta = conn.bytes_read - conn.last_report_read;
+      let written_delta = conn.bytes_written - conn.last_report_written;
+      total_read += read_delta;
+      total_written += written_delta;
+      println!(
+        "[{}] {:.1} B/s in, {:.1} B/s out",
+        conn.id,
+        read_delta as f64 / elapsed,
+        written_delta as f64 / elapsed
+      );
+      conn.last_report_read = conn.bytes_read;
+      conn.last_report_written = conn.bytes_written;
+    }
+    println!(
+      "Total: {:.1} B/s in, {:.1} B/s out across {} connection(s)",
+      total_read as f64 / elapsed,
+      total_written as f64 / elapsed,
+      self.connections.len()
+    );
+    self.last_report = now;
+  }
 }
 
-fn main() -> std::io::Result<()> {
-  let connections = Connections::new(); /* Initialize struct containing all active connections */
+/* Typed commands a client may send in JSON protocol mode, one JSON object
+ * per framed payload */
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientCommand {
+  Msg { body: String },
+  Nick { name: String },
+  List,
+  Quit,
+}
 
-  /* Parse arguments */
-  let args: Vec<String> = env::args().collect();
-  let port = if args.len() > 1 {
-    args[1].parse::<u16>().expect("Port must be a number")
-  } else {
-    1300
-  };
-  let addr = if args.len() > 2 {
-    IpAddr::from_str(&args[2]).expect("Address must be valid")
-  } else {
-    IpAddr::from(Ipv4Addr::new(127,0,0,1))
+/* Typed replies the server sends in JSON protocol mode, broadcast or
+ * directed depending on the command */
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerEvent {
+  Msg { from: String, body: String },
+  Joined { name: String },
+  Left { name: String },
+  List { connections: Vec<ListEntry> },
+  Error { message: String },
+  Shutdown,
+}
+
+/* One row of a `list` reply */
+#[derive(Debug, Serialize)]
+struct ListEntry {
+  id: u32,
+  name: Option<String>,
+}
+
+/* One entry in the persisted message log: a broadcast payload stamped with
+ * when it went out, who (if anyone) sent it, and which wire protocol it
+ * was broadcast under so a later restart in the other mode does not
+ * replay payloads the client can't parse */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LogEntry {
+  timestamp: String,
+  sender_id: Option<u32>,
+  json_mode: bool,
+  payload: String,
+}
+
+/* Length-prefix a payload: 4-byte big-endian length followed by the bytes */
+fn frame(buf: &[u8]) -> Vec<u8> {
+  let mut framed = Vec::with_capacity(4 + buf.len());
+  framed.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+  framed.extend_from_slice(buf);
+  framed
+}
+
+/* Append one log entry to the history file as a single JSON line, creating
+ * the file if this is the first entry */
+fn append_log_entry(path: &Path, entry: &LogEntry) -> io::Result<()> {
+  let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+  let line = serde_json::to_string(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  writeln!(file, "{}", line)
+}
+
+/* Reload the message log from disk at startup, discarding entries logged
+ * under the other wire protocol (a restart that switches between plain
+ * and json mode should not replay payloads the new mode can't parse) and
+ * keeping only the most recent `capacity` of what remains; a missing or
+ * unreadable file just starts with an empty log */
+fn load_log(path: &Path, capacity: usize, json_mode: bool) -> VecDeque<LogEntry> {
+  let contents = match fs::read_to_string(path) {
+    Ok(contents) => contents,
+    Err(_e) => return VecDeque::new(),
   };
-  
-  /* Create TCPListener */
-  let socket_addr = SocketAddr::from((addr,port)); 
-  let socket = TcpListener::bind(socket_addr)?;
-  socket.set_nonblocking(true).expect("Unable to set non-blocking");
-  println!("Listening on {}", socket_addr);
+  let mut log: VecDeque<LogEntry> = contents
+    .lines()
+    .filter_map(|line| serde_json::from_str::<LogEntry>(line).ok())
+    .filter(|entry| entry.json_mode == json_mode)
+    .collect();
+  while log.len() > capacity {
+    log.pop_front();
+  }
+  log
+}
+
+/* Parsed command-line configuration, see `parse_args` for the accepted
+ * positionals and flags */
+struct Args {
+  port: u16,
+  addr: IpAddr,
+  rate_limit: Option<u64>,
+  json_mode: bool,
+  log_capacity: usize,
+  log_path: PathBuf,
+}
 
-  /* Accept connections in infinite loop */
-  for stream in socket.incoming() {
-    match stream {
-      Ok(stream) => {
-        /* Set stream to non-blocking as read/write called from multiple threads */
-        stream.set_nonblocking(true).expect("Unable to set non-blocking");
-        /* Store stream in Mutex for locking, and create struct to hold references */
-        let conn = Conn { 
-          stream: Arc::new(Mutex::new(stream)),
-          connections: connections.clone(),
-        };
-        /* Spawn the handler thread */
-        thread::spawn(move || handle_stream(conn));
+/* Parse the port and address as leading positionals (defaulting to 1300 and
+ * 127.0.0.1) and everything else as flags, so any subset of the optional
+ * knobs can be set without having to supply placeholders for the others:
+ *   rust-tcp-broadcast [port] [addr] [--rate-limit N] [--json]
+ *                      [--log-capacity N] [--log-path PATH] */
+fn parse_args(args: &[String]) -> Args {
+  let mut positional = Vec::new();
+  let mut rate_limit = None;
+  let mut json_mode = false;
+  let mut log_capacity = DEFAULT_LOG_CAPACITY;
+  let mut log_path = PathBuf::from(DEFAULT_LOG_PATH);
+
+  let mut iter = args.iter();
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "--rate-limit" => {
+        let value = iter.next().expect("--rate-limit requires a value");
+        rate_limit = Some(value.parse::<u64>().expect("Rate limit must be a number"));
       },
-      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-        /* Sleep for a bit when blocking error, an implementation of wait_for_fd would be better */
-        thread::sleep(Duration::from_millis(10));
-        continue;
+      "--json" => json_mode = true,
+      "--log-capacity" => {
+        let value = iter.next().expect("--log-capacity requires a value");
+        log_capacity = value.parse::<usize>().expect("Log capacity must be a number");
+      },
+      "--log-path" => {
+        let value = iter.next().expect("--log-path requires a value");
+        log_path = PathBuf::from(value);
       },
-      Err(e) => panic!("Encountered IO error: {}", e),
+      other => positional.push(other),
     }
   }
 
+  let port = match positional.first() {
+    Some(arg) => arg.parse::<u16>().expect("Port must be a number"),
+    None => 1300,
+  };
+  let addr = match positional.get(1) {
+    Some(arg) => IpAddr::from_str(arg).expect("Address must be valid"),
+    None => IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+  };
+
+  Args { port, addr, rate_limit, json_mode, log_capacity, log_path }
+}
+
+fn main() -> io::Result<()> {
+  /* Parse arguments */
+  let raw_args: Vec<String> = env::args().skip(1).collect();
+  let Args { port, addr, rate_limit, json_mode, log_capacity, log_path } = parse_args(&raw_args);
+
+  /* Reload any history left over from a previous run before accepting
+   * connections so a restart does not lose the replay buffer */
+  let log = load_log(&log_path, log_capacity, json_mode);
+  println!("Loaded {} history entries from {}", log.len(), log_path.display());
+
+  /* Create the event loop and the listening socket */
+  let mut poll = Poll::new()?;
+  let socket_addr = SocketAddr::from((addr, port));
+  let mut listener = TcpListener::bind(socket_addr)?;
+  poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+  println!("Listening on {}", socket_addr);
+
+  let connections = Rc::new(RefCell::new(Connections::new(
+    poll.registry().try_clone()?,
+    rate_limit,
+    json_mode,
+    log,
+    log_capacity,
+    Some(log_path),
+    Instant::now(),
+  )));
+  let mut events = Events::with_capacity(1024);
+
+  /* Set once Ctrl-C or SIGTERM is received, checked at the top of every
+   * loop iteration so the accept loop exits in place of running forever */
+  let shutdown_requested = Arc::new(AtomicBool::new(false));
+  {
+    let shutdown_requested = shutdown_requested.clone();
+    ctrlc::set_handler(move || {
+      shutdown_requested.store(true, Ordering::SeqCst);
+    }).expect("Error installing shutdown handler");
+  }
+
+  while !shutdown_requested.load(Ordering::SeqCst) {
+    /* Wake up regularly even with no readiness events so rate-limit windows
+     * roll over and the throughput report keeps printing. A signal delivered
+     * while blocked in epoll_wait interrupts the call rather than setting
+     * shutdown_requested and returning normally, so retry instead of letting
+     * it bubble up as an error - the flag is rechecked at the top of the loop. */
+    match poll.poll(&mut events, Some(TICK_INTERVAL)) {
+      Ok(()) => {},
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    }
+
+    for event in events.iter() {
+      match event.token() {
+        LISTENER => {
+          /* Accept in a loop until there is nothing left to accept */
+          loop {
+            match listener.accept() {
+              Ok((stream, _addr)) => {
+                let id = connections.borrow_mut().store(stream, Instant::now())?;
+                println!("[{}] Connected...", id);
+              },
+              Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+              Err(e) => return Err(e),
+            }
+          }
+        },
+        Token(raw) => {
+          let id = raw as u32;
+          let mut keep = true;
+          if event.is_readable() {
+            keep = connections.borrow_mut().handle_readable(id);
+          }
+          if keep && event.is_writable() {
+            keep = connections.borrow_mut().handle_writable(id);
+          }
+          if !keep {
+            let mut connections = connections.borrow_mut();
+            if let Some(name) = connections.name_of(id) {
+              connections.announce_leave(id, name);
+            }
+            connections.remove(id);
+            println!("[{}] Disconnected...", id);
+          }
+        },
+      }
+    }
+
+    connections.borrow_mut().tick();
+  }
+
+  /* Give every connection a shutdown notice and close its socket cleanly
+   * before the process exits. Not persisted: it describes the server's
+   * state at this instant, not chat history, and replaying "shutting down"
+   * to a client connecting after a clean restart would be wrong, as would
+   * letting it occupy a slot in the capacity-bounded log on every restart. */
+  println!("Shutting down...");
+  let mut connections = connections.borrow_mut();
+  if connections.json_mode {
+    connections.broadcast_event(None, &ServerEvent::Shutdown, false);
+  } else {
+    connections.broadcast(None, b"Server is shutting down", false);
+  }
+  connections.shutdown_all();
   Ok(())
 }